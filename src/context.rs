@@ -0,0 +1,314 @@
+// Copyright (c) 2023 Murilo Ijanc' <mbsd@m0x.ru>
+//
+// Permission to use, copy, modify, and distribute this software for any
+// purpose with or without fee is hereby granted, provided that the above
+// copyright notice and this permission notice appear in all copies.
+//
+// THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+// WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+// MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+// ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+// WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+// ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+// OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+
+//! Tars and gzips a real build context directory, honoring `.dockerignore`,
+//! for builds whose `COPY`/`ADD` instructions need more than a bare
+//! Dockerfile.
+
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone)]
+struct IgnoreRule {
+    negate: bool,
+    anchored: bool,
+    dir_only: bool,
+    pattern: String,
+}
+
+fn parse_dockerignore(content: &str) -> Vec<IgnoreRule> {
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            let negate = line.starts_with('!');
+            let line = if negate { &line[1..] } else { line };
+            let anchored = line.starts_with('/');
+            let line = line.trim_start_matches('/');
+            let dir_only = line.ends_with('/');
+            let pattern = line.trim_end_matches('/').to_string();
+            IgnoreRule {
+                negate,
+                anchored,
+                dir_only,
+                pattern,
+            }
+        })
+        .collect()
+}
+
+/// Matches a single `*`/`?` glob segment (no `/`) against a path segment.
+fn segment_matches(pattern: &[u8], segment: &[u8]) -> bool {
+    match (pattern.first(), segment.first()) {
+        (None, None) => true,
+        (Some(b'*'), _) => {
+            (0..=segment.len()).any(|i| segment_matches(&pattern[1..], &segment[i..]))
+        }
+        (Some(b'?'), Some(_)) => segment_matches(&pattern[1..], &segment[1..]),
+        (Some(p), Some(s)) if p == s => segment_matches(&pattern[1..], &segment[1..]),
+        _ => false,
+    }
+}
+
+/// Matches a `/`-separated pattern (which may contain `**`) against a
+/// `/`-separated relative path.
+fn glob_matches(pattern: &[&str], path: &[&str]) -> bool {
+    match (pattern.first(), path.first()) {
+        (None, None) => true,
+        (None, Some(_)) => false,
+        (Some(&"**"), _) => {
+            if pattern.len() == 1 {
+                return true;
+            }
+            (0..=path.len()).any(|i| glob_matches(&pattern[1..], &path[i..]))
+        }
+        (Some(_), None) => false,
+        (Some(p), Some(s)) => {
+            segment_matches(p.as_bytes(), s.as_bytes())
+                && glob_matches(&pattern[1..], &path[1..])
+        }
+    }
+}
+
+fn rule_matches(rule: &IgnoreRule, rel_path: &str, is_dir: bool) -> bool {
+    if rule.dir_only && !is_dir {
+        return false;
+    }
+
+    let pattern_segments: Vec<&str> = rule.pattern.split('/').collect();
+    let path_segments: Vec<&str> = rel_path.split('/').collect();
+
+    if rule.anchored || rule.pattern.contains('/') {
+        glob_matches(&pattern_segments, &path_segments)
+    } else {
+        // An unanchored single-segment pattern matches at any depth, as
+        // `.dockerignore`/`.gitignore` do.
+        (0..path_segments.len())
+            .any(|i| glob_matches(&pattern_segments, &path_segments[i..]))
+    }
+}
+
+/// The last matching rule wins, and a later `!pattern` can re-include a
+/// path excluded by an earlier rule.
+fn is_ignored(rules: &[IgnoreRule], rel_path: &str, is_dir: bool) -> bool {
+    let mut ignored = false;
+    for rule in rules {
+        if rule_matches(rule, rel_path, is_dir) {
+            ignored = !rule.negate;
+        }
+    }
+    ignored
+}
+
+fn collect_files(
+    root: &Path,
+    dir: &Path,
+    rules: &[IgnoreRule],
+    out: &mut Vec<PathBuf>,
+) -> io::Result<()> {
+    let mut entries: Vec<_> = fs::read_dir(dir)?.collect::<Result<_, _>>()?;
+    entries.sort_by_key(|e| e.path());
+
+    for entry in entries {
+        let path = entry.path();
+        let rel = path
+            .strip_prefix(root)
+            .unwrap_or(&path)
+            .to_string_lossy()
+            .replace('\\', "/");
+        let is_dir = path.is_dir();
+
+        if is_ignored(rules, &rel, is_dir) {
+            continue;
+        }
+
+        if is_dir {
+            collect_files(root, &path, rules, out)?;
+        } else {
+            out.push(path);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn file_mode(metadata: &fs::Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode()
+}
+
+#[cfg(not(unix))]
+fn file_mode(_metadata: &fs::Metadata) -> u32 {
+    0o644
+}
+
+fn gzip(bytes: &[u8]) -> io::Result<Vec<u8>> {
+    let mut encoder =
+        flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    encoder.write_all(bytes)?;
+    encoder.finish()
+}
+
+/// Tars up `context_dir`, excluding anything matched by its
+/// `.dockerignore` (gitignore-style globbing: `**`, `*`, leading `/`
+/// anchoring, `!` negation, and trailing-`/` directory patterns), and
+/// gzips the result for `docker.build_image`. `dockerfile_path` is read
+/// and stored as `Dockerfile` regardless of its real name, mirroring
+/// `docker build -f`. The `.dockerignore` file itself is never part of
+/// the build context and is always excluded, independent of its rules.
+pub fn compress_context(
+    context_dir: &Path,
+    dockerfile_path: &Path,
+) -> io::Result<Vec<u8>> {
+    let dockerignore_path = context_dir.join(".dockerignore");
+    let rules = if dockerignore_path.exists() {
+        parse_dockerignore(&fs::read_to_string(&dockerignore_path)?)
+    } else {
+        Vec::new()
+    };
+
+    let canonical_dockerfile = dockerfile_path
+        .canonicalize()
+        .unwrap_or_else(|_| dockerfile_path.to_path_buf());
+    let canonical_dockerignore = dockerignore_path
+        .canonicalize()
+        .unwrap_or_else(|_| dockerignore_path.clone());
+
+    let mut files = Vec::new();
+    collect_files(context_dir, context_dir, &rules, &mut files)?;
+
+    let mut tar = tar::Builder::new(Vec::new());
+    for path in files {
+        let canonical_path = path.canonicalize().unwrap_or_else(|_| path.clone());
+        if canonical_path == canonical_dockerfile || canonical_path == canonical_dockerignore {
+            continue;
+        }
+
+        let rel = path.strip_prefix(context_dir).unwrap_or(&path);
+        let metadata = fs::metadata(&path)?;
+        let mut header = tar::Header::new_gnu();
+        header.set_path(rel)?;
+        header.set_size(metadata.len());
+        header.set_mode(file_mode(&metadata));
+        header.set_cksum();
+
+        let mut file = fs::File::open(&path)?;
+        tar.append(&header, &mut file)?;
+    }
+
+    let dockerfile_content = fs::read(dockerfile_path)?;
+    let mut dockerfile_header = tar::Header::new_gnu();
+    dockerfile_header.set_path("Dockerfile")?;
+    dockerfile_header.set_size(dockerfile_content.len() as u64);
+    dockerfile_header.set_mode(0o644);
+    dockerfile_header.set_cksum();
+    tar.append(&dockerfile_header, dockerfile_content.as_slice())?;
+
+    gzip(&tar.into_inner()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        let n = COUNTER.fetch_add(1, Ordering::Relaxed);
+        let dir =
+            std::env::temp_dir().join(format!("kp-container-context-{name}-{nanos}-{n}"));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn rules_for(content: &str) -> Vec<IgnoreRule> {
+        parse_dockerignore(content)
+    }
+
+    #[test]
+    fn unanchored_pattern_matches_at_any_depth() {
+        let rules = rules_for("*.log");
+        assert!(is_ignored(&rules, "debug.log", false));
+        assert!(is_ignored(&rules, "nested/deep/debug.log", false));
+        assert!(!is_ignored(&rules, "debug.txt", false));
+    }
+
+    #[test]
+    fn anchored_pattern_only_matches_at_root() {
+        let rules = rules_for("/build");
+        assert!(is_ignored(&rules, "build", true));
+        assert!(!is_ignored(&rules, "nested/build", true));
+    }
+
+    #[test]
+    fn dir_only_pattern_skips_files() {
+        let rules = rules_for("vendor/");
+        assert!(is_ignored(&rules, "vendor", true));
+        assert!(!is_ignored(&rules, "vendor", false));
+    }
+
+    #[test]
+    fn double_star_matches_any_number_of_segments() {
+        let rules = rules_for("**/*.tmp");
+        assert!(is_ignored(&rules, "a.tmp", false));
+        assert!(is_ignored(&rules, "a/b/c.tmp", false));
+        assert!(!is_ignored(&rules, "a/b/c.txt", false));
+    }
+
+    #[test]
+    fn negation_re_includes_a_later_match() {
+        let rules = rules_for("*.log\n!important.log\n");
+        assert!(is_ignored(&rules, "debug.log", false));
+        assert!(!is_ignored(&rules, "important.log", false));
+    }
+
+    #[test]
+    fn compress_context_excludes_ignored_files_and_embeds_dockerfile() {
+        let dir = temp_dir("compress");
+        fs::write(dir.join(".dockerignore"), "*.log\nnode_modules/\n").unwrap();
+        fs::write(dir.join("Dockerfile"), "FROM alpine\nCOPY . /app\n").unwrap();
+        fs::write(dir.join("main.rs"), "fn main() {}\n").unwrap();
+        fs::write(dir.join("debug.log"), "noisy\n").unwrap();
+        fs::create_dir_all(dir.join("node_modules")).unwrap();
+        fs::write(dir.join("node_modules").join("pkg.json"), "{}\n").unwrap();
+
+        let gz = compress_context(&dir, &dir.join("Dockerfile")).unwrap();
+
+        let decoder = flate2::read::GzDecoder::new(gz.as_slice());
+        let mut archive = tar::Archive::new(decoder);
+        let mut names: Vec<String> = archive
+            .entries()
+            .unwrap()
+            .map(|entry| {
+                entry
+                    .unwrap()
+                    .path()
+                    .unwrap()
+                    .to_string_lossy()
+                    .into_owned()
+            })
+            .collect();
+        names.sort();
+
+        assert_eq!(names, vec!["Dockerfile".to_string(), "main.rs".to_string()]);
+    }
+}
@@ -0,0 +1,401 @@
+// Copyright (c) 2023 Murilo Ijanc' <mbsd@m0x.ru>
+//
+// Permission to use, copy, modify, and distribute this software for any
+// purpose with or without fee is hereby granted, provided that the above
+// copyright notice and this permission notice appear in all copies.
+//
+// THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+// WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+// MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+// ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+// WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+// ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+// OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+
+//! Registry credential providers. `provider_for_registry` picks one by
+//! matching the registry hostname, so pushing to ECR, Docker Hub, GHCR or
+//! any private registry goes through the same [`CredentialProvider`]
+//! interface instead of a hard-coded AWS call.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io::Write as _;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+use async_trait::async_trait;
+use aws_config::meta::region::RegionProviderChain;
+use aws_config::Region;
+use base64::prelude::*;
+use bollard::auth::DockerCredentials;
+use serde::Deserialize;
+
+#[derive(Debug)]
+pub enum CredentialError {
+    Io(std::io::Error),
+    Json(String),
+    Process(String),
+    MissingAuth(String),
+    Aws(String),
+}
+
+impl fmt::Display for CredentialError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CredentialError::Io(e) => write!(f, "i/o error: {e}"),
+            CredentialError::Json(e) => write!(f, "invalid docker config: {e}"),
+            CredentialError::Process(e) => write!(f, "credential helper failed: {e}"),
+            CredentialError::MissingAuth(registry) => {
+                write!(f, "no credentials found for registry {registry}")
+            }
+            CredentialError::Aws(e) => write!(f, "aws error: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for CredentialError {}
+
+impl From<std::io::Error> for CredentialError {
+    fn from(e: std::io::Error) -> Self {
+        CredentialError::Io(e)
+    }
+}
+
+/// Resolves the `DockerCredentials` bollard needs to authenticate a pull
+/// or push against a specific registry.
+#[async_trait]
+pub trait CredentialProvider: Send + Sync {
+    async fn credentials(
+        &self,
+        registry: &str,
+    ) -> Result<DockerCredentials, CredentialError>;
+}
+
+/// Picks a [`CredentialProvider`] for `registry` by hostname: ECR hosts
+/// (`*.dkr.ecr.*.amazonaws.com`) get the AWS-aware [`EcrProvider`],
+/// everything else falls back to [`DockerConfigProvider`] reading
+/// `~/.docker/config.json`.
+pub fn provider_for_registry(registry: &str) -> Box<dyn CredentialProvider> {
+    if ecr_region(registry).is_some() {
+        Box::new(EcrProvider)
+    } else {
+        Box::new(DockerConfigProvider::default())
+    }
+}
+
+/// Extracts the region from an ECR host like
+/// `123456789012.dkr.ecr.us-east-1.amazonaws.com`.
+fn ecr_region(registry: &str) -> Option<String> {
+    let host = registry.split('/').next().unwrap_or(registry);
+    let labels: Vec<&str> = host.split('.').collect();
+    match labels.as_slice() {
+        [_account, "dkr", "ecr", region, "amazonaws", "com", ..] => {
+            Some(region.to_string())
+        }
+        _ => None,
+    }
+}
+
+/// AWS ECR, authenticating via `GetAuthorizationToken` for the region
+/// embedded in the registry hostname (falling back to `us-east-1`).
+pub struct EcrProvider;
+
+#[async_trait]
+impl CredentialProvider for EcrProvider {
+    async fn credentials(
+        &self,
+        registry: &str,
+    ) -> Result<DockerCredentials, CredentialError> {
+        let region =
+            ecr_region(registry).unwrap_or_else(|| "us-east-1".to_string());
+
+        let region_provider =
+            RegionProviderChain::first_try(Some(region.clone()).map(Region::new))
+                .or_default_provider()
+                .or_else(Region::new(region));
+
+        let shared_config =
+            aws_config::from_env().region(region_provider).load().await;
+        let client = aws_sdk_ecr::Client::new(&shared_config);
+        let token = client
+            .get_authorization_token()
+            .send()
+            .await
+            .map_err(|e| CredentialError::Aws(e.to_string()))?;
+
+        let authorization = token
+            .authorization_data()
+            .first()
+            .and_then(|d| d.authorization_token())
+            .ok_or_else(|| CredentialError::MissingAuth(registry.to_string()))?;
+
+        let decoded = BASE64_STANDARD
+            .decode(authorization.as_bytes())
+            .map_err(|e| CredentialError::Json(e.to_string()))?;
+        let decoded = String::from_utf8(decoded)
+            .map_err(|e| CredentialError::Json(e.to_string()))?;
+        let (username, password) = decoded
+            .split_once(':')
+            .ok_or_else(|| CredentialError::MissingAuth(registry.to_string()))?;
+
+        Ok(DockerCredentials {
+            username: Some(username.to_string()),
+            password: Some(password.to_string()),
+            serveraddress: Some(registry.to_string()),
+            ..Default::default()
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct DockerConfigFile {
+    #[serde(default)]
+    auths: HashMap<String, DockerAuthEntry>,
+    #[serde(rename = "credsStore")]
+    creds_store: Option<String>,
+    #[serde(rename = "credHelpers", default)]
+    cred_helpers: HashMap<String, String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct DockerAuthEntry {
+    auth: Option<String>,
+}
+
+/// Reads `~/.docker/config.json` (or `$DOCKER_CONFIG/config.json`),
+/// supporting plain base64 `auths` entries as well as `credsStore`/
+/// `credHelpers` shelling out to `docker-credential-<helper>`.
+pub struct DockerConfigProvider {
+    config_path: PathBuf,
+}
+
+impl DockerConfigProvider {
+    pub fn new(config_path: PathBuf) -> Self {
+        Self { config_path }
+    }
+}
+
+impl Default for DockerConfigProvider {
+    fn default() -> Self {
+        Self::new(default_docker_config_path())
+    }
+}
+
+fn default_docker_config_path() -> PathBuf {
+    if let Some(dir) = std::env::var_os("DOCKER_CONFIG") {
+        return PathBuf::from(dir).join("config.json");
+    }
+    let home = std::env::var_os("HOME").map(PathBuf::from).unwrap_or_default();
+    home.join(".docker").join("config.json")
+}
+
+#[async_trait]
+impl CredentialProvider for DockerConfigProvider {
+    async fn credentials(
+        &self,
+        registry: &str,
+    ) -> Result<DockerCredentials, CredentialError> {
+        let content = std::fs::read_to_string(&self.config_path)?;
+        let config: DockerConfigFile = serde_json::from_str(&content)
+            .map_err(|e| CredentialError::Json(e.to_string()))?;
+
+        if let Some(entry) = config.auths.get(registry) {
+            if let Some(auth) = &entry.auth {
+                return decode_basic_auth(auth, registry);
+            }
+        }
+
+        if let Some(helper) = config.cred_helpers.get(registry) {
+            return run_credential_helper(helper, registry);
+        }
+
+        if let Some(helper) = &config.creds_store {
+            return run_credential_helper(helper, registry);
+        }
+
+        Err(CredentialError::MissingAuth(registry.to_string()))
+    }
+}
+
+fn decode_basic_auth(
+    auth: &str,
+    registry: &str,
+) -> Result<DockerCredentials, CredentialError> {
+    let decoded = BASE64_STANDARD
+        .decode(auth.as_bytes())
+        .map_err(|e| CredentialError::Json(e.to_string()))?;
+    let decoded = String::from_utf8(decoded)
+        .map_err(|e| CredentialError::Json(e.to_string()))?;
+    let (username, password) = decoded
+        .split_once(':')
+        .ok_or_else(|| CredentialError::MissingAuth(registry.to_string()))?;
+
+    Ok(DockerCredentials {
+        username: Some(username.to_string()),
+        password: Some(password.to_string()),
+        serveraddress: Some(registry.to_string()),
+        ..Default::default()
+    })
+}
+
+#[derive(Debug, Deserialize)]
+struct CredentialHelperOutput {
+    #[serde(rename = "Username")]
+    username: String,
+    #[serde(rename = "Secret")]
+    secret: String,
+}
+
+fn run_credential_helper(
+    helper: &str,
+    registry: &str,
+) -> Result<DockerCredentials, CredentialError> {
+    let mut child = Command::new(format!("docker-credential-{helper}"))
+        .arg("get")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| CredentialError::Process(e.to_string()))?;
+
+    child
+        .stdin
+        .take()
+        .expect("piped stdin")
+        .write_all(registry.as_bytes())
+        .map_err(|e| CredentialError::Process(e.to_string()))?;
+
+    let output = child
+        .wait_with_output()
+        .map_err(|e| CredentialError::Process(e.to_string()))?;
+    if !output.status.success() {
+        return Err(CredentialError::Process(
+            String::from_utf8_lossy(&output.stderr).to_string(),
+        ));
+    }
+
+    let parsed: CredentialHelperOutput = serde_json::from_slice(&output.stdout)
+        .map_err(|e| CredentialError::Json(e.to_string()))?;
+
+    Ok(DockerCredentials {
+        username: Some(parsed.username),
+        password: Some(parsed.secret),
+        serveraddress: Some(registry.to_string()),
+        ..Default::default()
+    })
+}
+
+/// Explicit username/password, for registries that need neither AWS nor
+/// a local Docker config (e.g. credentials sourced from a secrets
+/// manager by the caller).
+pub struct BasicAuthProvider {
+    username: String,
+    password: String,
+}
+
+impl BasicAuthProvider {
+    pub fn new(username: impl Into<String>, password: impl Into<String>) -> Self {
+        Self {
+            username: username.into(),
+            password: password.into(),
+        }
+    }
+}
+
+#[async_trait]
+impl CredentialProvider for BasicAuthProvider {
+    async fn credentials(
+        &self,
+        registry: &str,
+    ) -> Result<DockerCredentials, CredentialError> {
+        Ok(DockerCredentials {
+            username: Some(self.username.clone()),
+            password: Some(self.password.clone()),
+            serveraddress: Some(registry.to_string()),
+            ..Default::default()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        let n = COUNTER.fetch_add(1, Ordering::Relaxed);
+        let dir = std::env::temp_dir()
+            .join(format!("kp-container-credentials-{name}-{nanos}-{n}"));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn ecr_region_matches_ecr_hostnames() {
+        assert_eq!(
+            ecr_region("123456789012.dkr.ecr.us-east-1.amazonaws.com"),
+            Some("us-east-1".to_string())
+        );
+        assert_eq!(
+            ecr_region("123456789012.dkr.ecr.eu-west-1.amazonaws.com/my-repo"),
+            Some("eu-west-1".to_string())
+        );
+    }
+
+    #[test]
+    fn ecr_region_none_for_other_hosts() {
+        assert_eq!(ecr_region("docker.io"), None);
+        assert_eq!(ecr_region("ghcr.io"), None);
+        assert_eq!(ecr_region("registry.example.com"), None);
+    }
+
+    #[tokio::test]
+    async fn docker_config_provider_reads_plain_auths_entry() {
+        let dir = temp_dir("auths");
+        let auth = BASE64_STANDARD.encode("alice:hunter2");
+        fs::write(
+            dir.join("config.json"),
+            format!(r#"{{"auths":{{"registry.example.com":{{"auth":"{auth}"}}}}}}"#),
+        )
+        .unwrap();
+
+        let provider = DockerConfigProvider::new(dir.join("config.json"));
+        let credentials = provider.credentials("registry.example.com").await.unwrap();
+
+        assert_eq!(credentials.username, Some("alice".to_string()));
+        assert_eq!(credentials.password, Some("hunter2".to_string()));
+    }
+
+    #[tokio::test]
+    async fn docker_config_provider_errors_when_registry_missing() {
+        let dir = temp_dir("missing");
+        fs::write(dir.join("config.json"), r#"{"auths":{}}"#).unwrap();
+
+        let provider = DockerConfigProvider::new(dir.join("config.json"));
+        let result = provider.credentials("registry.example.com").await;
+
+        assert!(matches!(result, Err(CredentialError::MissingAuth(_))));
+    }
+
+    #[tokio::test]
+    async fn basic_auth_provider_returns_given_credentials() {
+        let provider = BasicAuthProvider::new("bob", "swordfish");
+        let credentials = provider.credentials("registry.example.com").await.unwrap();
+
+        assert_eq!(credentials.username, Some("bob".to_string()));
+        assert_eq!(credentials.password, Some("swordfish".to_string()));
+        assert_eq!(
+            credentials.serveraddress,
+            Some("registry.example.com".to_string())
+        );
+    }
+}
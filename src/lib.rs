@@ -12,85 +12,107 @@
 // ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
 // OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
 
-//
-// TODO: tag, auth, push, multi arch
-//
-use aws_config::meta::region::RegionProviderChain;
-use aws_config::Region;
-use bollard::image::{BuildImageOptions, BuilderVersion};
+use bollard::image::{
+    BuildImageOptions, BuilderVersion, PushImageOptions, TagImageOptions,
+};
 use bollard::models::BuildInfoAux;
 use bollard::Docker;
-use dockerfile_parser::Dockerfile;
+use std::fmt;
+use std::path::Path;
 use std::str::FromStr;
 
 use futures_util::stream::StreamExt;
 
-use base64::prelude::*;
 use std::io::Write;
 
-async fn get_credential() -> (String, String) {
-    // Struct credentials to push
-    // https://docs.rs/bollard/latest/bollard/auth/struct.DockerCredentials.html
-    //
-    // AWS ECR
-    // https://docs.rs/aws-sdk-ecr/latest/aws_sdk_ecr/types/struct.AuthorizationData.html
-    //
+mod context;
+mod credentials;
+mod manifest;
+mod metadata;
+mod preprocess;
+
+pub use credentials::{
+    BasicAuthProvider, CredentialError, CredentialProvider, DockerConfigProvider,
+    EcrProvider,
+};
 
-    let region_provider =
-        RegionProviderChain::first_try(Some("us-east-1").map(Region::new))
-            .or_default_provider()
-            .or_else(Region::new("us-east-1"));
-
-    let shared_config =
-        aws_config::from_env().region(region_provider).load().await;
-    let client = aws_sdk_ecr::Client::new(&shared_config);
-    let token = client.get_authorization_token().send().await.unwrap();
-    let authorization =
-        token.authorization_data()[0].authorization_token().unwrap();
-    let data = BASE64_STANDARD.decode(authorization.as_bytes()).unwrap();
-    let parts = String::from_utf8(data).unwrap();
-    let parts: Vec<&str> = parts.split(':').collect();
-    // dbg!(&parts);
-    // Example in go for split AuthorizationData
-    // https://github.com/chialab/aws-ecr-get-login-password/blob/main/main.go
-    (parts[0].to_string(), parts[1].to_string())
-}
-
-fn get_port_from_dockerfile(dockerfile: &str) -> Option<u16> {
-    let dockerfile = Dockerfile::parse(dockerfile).unwrap();
-    let mut port: u16 = 0;
-
-    for stage in dockerfile.iter_stages() {
-        println!(
-            "stage #{} (parent: {:?}, root: {:?})",
-            stage.index, stage.parent, stage.root
-        );
-
-        for ins in stage.instructions {
-            match ins {
-                dockerfile_parser::Instruction::Misc(misc) => {
-                    if misc.instruction.content.as_str() == "EXPOSE" {
-                        match misc.arguments.components.get(0).unwrap() {
-                            dockerfile_parser::BreakableStringComponent::String(c)
-                                => {
-                                    port = c.content.trim().parse().unwrap();
-                                    break;
-                                }
-                            _ => {},
-                        }
-                    }
-                }
-                _ => {}
+#[derive(Debug)]
+pub enum Error {
+    Docker(bollard::errors::Error),
+    Credential(CredentialError),
+    MissingDigest(String),
+    PushFailed(String),
+    Io(std::io::Error),
+    Metadata(metadata::MetadataError),
+    Manifest(manifest::ManifestError),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Docker(e) => write!(f, "docker error: {e}"),
+            Error::Credential(e) => write!(f, "credential error: {e}"),
+            Error::MissingDigest(repository) => {
+                write!(f, "push of {repository} reported no digest")
             }
+            Error::PushFailed(message) => write!(f, "push failed: {message}"),
+            Error::Io(e) => write!(f, "i/o error: {e}"),
+            Error::Metadata(e) => write!(f, "metadata error: {e}"),
+            Error::Manifest(e) => write!(f, "manifest error: {e}"),
         }
     }
-    if port == 0 {
-        None
-    } else {
-        Some(port)
+}
+
+impl std::error::Error for Error {}
+
+impl From<bollard::errors::Error> for Error {
+    fn from(e: bollard::errors::Error) -> Self {
+        Error::Docker(e)
+    }
+}
+
+impl From<CredentialError> for Error {
+    fn from(e: CredentialError) -> Self {
+        Error::Credential(e)
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<metadata::MetadataError> for Error {
+    fn from(e: metadata::MetadataError) -> Self {
+        Error::Metadata(e)
+    }
+}
+
+impl From<manifest::ManifestError> for Error {
+    fn from(e: manifest::ManifestError) -> Self {
+        Error::Manifest(e)
     }
 }
 
+/// Loads `dockerfile_path`, expanding `INCLUDE+` directives relative to
+/// its directory, and extracts the final stage's runtime metadata from
+/// the result. This is the actual Dockerfile-loading path: callers that
+/// only had raw Dockerfile content in hand should go through
+/// [`preprocess::expand_includes`] themselves before calling
+/// [`metadata::parse_metadata`] directly.
+pub fn load_dockerfile_metadata(
+    dockerfile_path: &Path,
+) -> Result<metadata::DockerfileMetadata, Error> {
+    let expanded = preprocess::load_dockerfile(dockerfile_path)?;
+    Ok(metadata::parse_metadata(&expanded)?)
+}
+
+
+/// Tars and gzips just the Dockerfile content, with no build context.
+/// Kept as the convenience path for builds that pull everything over the
+/// network; use [`context::compress_context`] once `COPY`/`ADD` need
+/// local files.
 fn compress(dockerfile: &str) -> Vec<u8> {
     let mut header = tar::Header::new_gnu();
     header.set_path("Dockerfile").unwrap();
@@ -109,13 +131,17 @@ fn compress(dockerfile: &str) -> Vec<u8> {
     c.finish().unwrap()
 }
 
-fn build_options(id: &str) -> BuildImageOptions<&str> {
+fn build_options<'a>(
+    id: &'a str,
+    platform: &'a str,
+) -> BuildImageOptions<&'a str> {
     BuildImageOptions {
         t: id,
         dockerfile: "Dockerfile",
         version: BuilderVersion::BuilderBuildKit,
         pull: true,
         session: Some(String::from(id)),
+        platform,
         ..Default::default()
     }
 }
@@ -124,9 +150,37 @@ async fn docker_connect() -> Docker {
     Docker::connect_with_socket_defaults().unwrap()
 }
 
-async fn build_image(docker: &Docker, id: &str, dockerfile_content: &str) {
-    let compressed = compress(dockerfile_content);
-    let build_image_options = build_options(id);
+/// Where a build's tarred-up contents come from: a bare Dockerfile with
+/// no local files, or a real context directory whose `COPY`/`ADD`
+/// instructions need files alongside it.
+enum BuildSource<'a> {
+    Dockerfile(&'a str),
+    Context {
+        context_dir: &'a Path,
+        dockerfile_path: &'a Path,
+    },
+}
+
+impl BuildSource<'_> {
+    fn compress(&self) -> Result<Vec<u8>, Error> {
+        match self {
+            BuildSource::Dockerfile(content) => Ok(compress(content)),
+            BuildSource::Context {
+                context_dir,
+                dockerfile_path,
+            } => Ok(context::compress_context(context_dir, dockerfile_path)?),
+        }
+    }
+}
+
+async fn build_image(
+    docker: &Docker,
+    id: &str,
+    source: BuildSource<'_>,
+    platform: &str,
+) -> Result<(), Error> {
+    let compressed = source.compress()?;
+    let build_image_options = build_options(id, platform);
 
     let mut image_build_stream =
         docker.build_image(build_image_options, None, Some(compressed.into()));
@@ -138,6 +192,159 @@ async fn build_image(docker: &Docker, id: &str, dockerfile_content: &str) {
     {
         println!("Response: {:?}", inner);
     }
+
+    Ok(())
+}
+
+/// Builds `id` for `platform` from the Dockerfile at `dockerfile_path`,
+/// expanding any `INCLUDE+` directives relative to its directory first.
+/// This is the real Dockerfile-loading path, as opposed to [`build_image`]
+/// which expects the caller to have already produced the final content.
+async fn build_image_from_path(
+    docker: &Docker,
+    id: &str,
+    dockerfile_path: &Path,
+    platform: &str,
+) -> Result<(), Error> {
+    let dockerfile_content = preprocess::load_dockerfile(dockerfile_path)?;
+    build_image(docker, id, BuildSource::Dockerfile(&dockerfile_content), platform).await
+}
+
+/// Builds `id` for `platform` from `context_dir`, tarring the whole
+/// directory (honoring `.dockerignore`) instead of just the Dockerfile at
+/// `dockerfile_path`, so `COPY`/`ADD` instructions can see local files.
+async fn build_image_with_context(
+    docker: &Docker,
+    id: &str,
+    context_dir: &Path,
+    dockerfile_path: &Path,
+    platform: &str,
+) -> Result<(), Error> {
+    build_image(
+        docker,
+        id,
+        BuildSource::Context {
+            context_dir,
+            dockerfile_path,
+        },
+        platform,
+    )
+    .await
+}
+
+/// Builds `id` once per entry in `platforms` (e.g. `["linux/amd64",
+/// "linux/arm64"]`), tagging each build `<id>-<os>-<arch>` (e.g.
+/// `myimage-linux-arm64`), pushes each one to
+/// `registry`, and returns the digest the registry assigned each push so
+/// they can be assembled into a manifest list via
+/// [`manifest::build_image_index`]. A manifest list must point at
+/// manifests the registry actually has, so each per-arch image has to
+/// land there before the index referencing it can.
+async fn build_multi_arch_image(
+    docker: &Docker,
+    id: &str,
+    dockerfile_content: &str,
+    platforms: &[String],
+    registry: &str,
+) -> Result<Vec<manifest::PlatformDigest>, Error> {
+    let mut digests = Vec::with_capacity(platforms.len());
+
+    for platform in platforms {
+        let arch_tag = format!("{id}-{}", platform.replace('/', "-"));
+        build_image(
+            docker,
+            &arch_tag,
+            BuildSource::Dockerfile(dockerfile_content),
+            platform,
+        )
+        .await?;
+
+        let digest = push_image(docker, &arch_tag, registry).await?;
+        let size = docker
+            .inspect_image(&arch_tag)
+            .await?
+            .size
+            .unwrap_or_default();
+        let (os, architecture) = manifest::split_platform(platform);
+
+        digests.push(manifest::PlatformDigest {
+            platform: platform.clone(),
+            os,
+            architecture,
+            digest,
+            size,
+        });
+    }
+
+    Ok(digests)
+}
+
+/// Builds `id` for every platform in `platforms`, pushes each per-arch
+/// image to `registry`, then assembles and pushes an OCI image index so
+/// `registry/id:latest` resolves to the right per-arch manifest
+/// everywhere — the single-invocation cross-arch publish path the
+/// per-arch [`build_multi_arch_image`] alone doesn't provide.
+async fn build_and_push_multi_arch_image(
+    docker: &Docker,
+    id: &str,
+    dockerfile_content: &str,
+    platforms: &[String],
+    registry: &str,
+) -> Result<(), Error> {
+    let digests =
+        build_multi_arch_image(docker, id, dockerfile_content, platforms, registry)
+            .await?;
+    let index = manifest::build_image_index(&digests);
+
+    let credentials =
+        credentials::provider_for_registry(registry).credentials(registry).await?;
+    manifest::push_manifest_list(registry, id, "latest", &credentials, &index).await?;
+    Ok(())
+}
+
+/// Tags and pushes `id` to `registry`, returning the digest the registry
+/// assigned the pushed manifest (read from the push stream's `aux`, the
+/// same value the registry reports via `Docker-Content-Digest`).
+async fn push_image(
+    docker: &Docker,
+    id: &str,
+    registry: &str,
+) -> Result<String, Error> {
+    let credentials =
+        credentials::provider_for_registry(registry).credentials(registry).await?;
+
+    let repository = format!("{}/{}", registry, id);
+    docker
+        .tag_image(
+            id,
+            Some(TagImageOptions {
+                repo: repository.as_str(),
+                tag: "latest",
+            }),
+        )
+        .await?;
+
+    let mut image_push_stream = docker.push_image(
+        &repository,
+        Some(PushImageOptions { tag: "latest" }),
+        Some(credentials),
+    );
+
+    let mut digest = None;
+    while let Some(result) = image_push_stream.next().await {
+        let info = result?;
+        if let Some(error) = info.error {
+            return Err(Error::PushFailed(error));
+        }
+        if let Some(aux) = &info.aux {
+            if let Some(d) = aux.get("Digest").and_then(|v| v.as_str()) {
+                digest = Some(d.to_string());
+            }
+            println!("Response: {:?}", aux);
+        }
+    }
+
+    digest.ok_or(Error::MissingDigest(repository))
 }
 
 #[cfg(test)]
@@ -146,7 +353,8 @@ mod tests {
 
     #[tokio::test]
     async fn aws_ecr_credential() {
-        let _credential = get_credential().await;
+        let registry = "123456789012.dkr.ecr.us-east-1.amazonaws.com";
+        let _credential = EcrProvider.credentials(registry).await;
         assert!(true);
     }
 
@@ -162,13 +370,45 @@ mod tests {
     // ",
     //     );
 
-    //     build_image(&client, "myimage", &dockerfile).await;
+    //     build_image(
+    //         &client,
+    //         "myimage",
+    //         BuildSource::Dockerfile(&dockerfile),
+    //         "linux/amd64",
+    //     )
+    //     .await
+    //     .unwrap();
 
     //     assert!(true);
     // }
 
+    // #[tokio::test]
+    // async fn docker_build_multi_arch_image() {
+    //     let client = docker_connect().await;
+    //     let dockerfile = String::from(
+    //         "FROM alpine
+    // RUN touch bollard.txt
+    // ENTRYPOINT ls bollard.txt
+    // ",
+    //     );
+    //     let platforms =
+    //         vec!["linux/amd64".to_string(), "linux/arm64".to_string()];
+    //
+    //     let digests = build_multi_arch_image(
+    //         &client,
+    //         "myimage",
+    //         &dockerfile,
+    //         &platforms,
+    //         "localhost:5000",
+    //     )
+    //     .await
+    //     .unwrap();
+    //
+    //     assert_eq!(platforms.len(), digests.len());
+    // }
+
     // #[test]
-    // fn get_port_dockerfile() {
+    // fn parse_dockerfile_metadata() {
     //     let dockerfile = String::from(
     //         "FROM alpine as builder1
     // RUN touch bollard.txt
@@ -178,8 +418,7 @@ mod tests {
     // ENTRYPOINT ls buildkit-bollard.txt
     //         "
     //     );
-    //     let port = get_port_from_dockerfile(&dockerfile);
-    //     assert!(port.is_some());
-    //     assert_eq!(3000 as u16, port.unwrap());
+    //     let meta = metadata::parse_metadata(&dockerfile).unwrap();
+    //     assert_eq!(vec![3000_u16], meta.exposed_ports);
     // }
 }
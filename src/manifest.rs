@@ -0,0 +1,242 @@
+// Copyright (c) 2023 Murilo Ijanc' <mbsd@m0x.ru>
+//
+// Permission to use, copy, modify, and distribute this software for any
+// purpose with or without fee is hereby granted, provided that the above
+// copyright notice and this permission notice appear in all copies.
+//
+// THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+// WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+// MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+// ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+// WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+// ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+// OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+
+//! OCI image index (manifest list) assembly for multi-arch builds.
+
+use std::collections::HashMap;
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+pub const OCI_IMAGE_INDEX_MEDIA_TYPE: &str =
+    "application/vnd.oci.image.index.v1+json";
+pub const OCI_IMAGE_MANIFEST_MEDIA_TYPE: &str =
+    "application/vnd.oci.image.manifest.v1+json";
+
+/// The manifest digest the registry assigned when a single
+/// `os/architecture` image was pushed.
+#[derive(Debug, Clone)]
+pub struct PlatformDigest {
+    pub platform: String,
+    pub os: String,
+    pub architecture: String,
+    pub digest: String,
+    pub size: i64,
+}
+
+/// Splits a `linux/arm64` style platform string into `(os, architecture)`.
+pub fn split_platform(platform: &str) -> (String, String) {
+    match platform.split_once('/') {
+        Some((os, arch)) => (os.to_string(), arch.to_string()),
+        None => ("linux".to_string(), platform.to_string()),
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Platform {
+    architecture: String,
+    os: String,
+}
+
+#[derive(Debug, Serialize)]
+struct ManifestDescriptor {
+    #[serde(rename = "mediaType")]
+    media_type: String,
+    digest: String,
+    size: i64,
+    platform: Platform,
+}
+
+/// An OCI image index, i.e. a manifest list pointing at one manifest per
+/// platform so a single tag resolves to the right image everywhere.
+#[derive(Debug, Serialize)]
+pub struct ImageIndex {
+    #[serde(rename = "schemaVersion")]
+    schema_version: u8,
+    #[serde(rename = "mediaType")]
+    media_type: String,
+    manifests: Vec<ManifestDescriptor>,
+}
+
+/// Assembles an [`ImageIndex`] from the digests of each platform build.
+pub fn build_image_index(digests: &[PlatformDigest]) -> ImageIndex {
+    let manifests = digests
+        .iter()
+        .map(|d| ManifestDescriptor {
+            media_type: OCI_IMAGE_MANIFEST_MEDIA_TYPE.to_string(),
+            digest: d.digest.clone(),
+            size: d.size,
+            platform: Platform {
+                architecture: d.architecture.clone(),
+                os: d.os.clone(),
+            },
+        })
+        .collect();
+
+    ImageIndex {
+        schema_version: 2,
+        media_type: OCI_IMAGE_INDEX_MEDIA_TYPE.to_string(),
+        manifests,
+    }
+}
+
+#[derive(Debug)]
+pub enum ManifestError {
+    Http(reqwest::Error),
+    NoToken(String),
+}
+
+impl fmt::Display for ManifestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ManifestError::Http(e) => write!(f, "registry request failed: {e}"),
+            ManifestError::NoToken(realm) => {
+                write!(f, "token endpoint {realm} returned no token")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ManifestError {}
+
+impl From<reqwest::Error> for ManifestError {
+    fn from(e: reqwest::Error) -> Self {
+        ManifestError::Http(e)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    token: Option<String>,
+    access_token: Option<String>,
+}
+
+/// Parses a `WWW-Authenticate: Bearer realm="...",service="...",scope="..."`
+/// challenge into its key/value parameters.
+fn parse_bearer_challenge(header: &str) -> HashMap<String, String> {
+    let mut params = HashMap::new();
+    let rest = header.trim_start_matches("Bearer").trim();
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut in_quotes = false;
+    for (i, c) in rest.char_indices() {
+        match c {
+            '"' => in_quotes = !in_quotes,
+            ',' if !in_quotes => {
+                parts.push(&rest[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    parts.push(&rest[start..]);
+    for part in parts {
+        if let Some((key, value)) = part.split_once('=') {
+            params.insert(
+                key.trim().to_string(),
+                value.trim().trim_matches('"').to_string(),
+            );
+        }
+    }
+    params
+}
+
+/// Exchanges registry credentials for a bearer token per the Docker
+/// Registry v2 token authentication spec, following the `realm`/`service`/
+/// `scope` from the `WWW-Authenticate` challenge (used by Docker Hub,
+/// GHCR, and most non-ECR registries that don't accept Basic directly).
+async fn bearer_token(
+    client: &reqwest::Client,
+    challenge: &str,
+    credentials: &bollard::auth::DockerCredentials,
+) -> Result<String, ManifestError> {
+    let params = parse_bearer_challenge(challenge);
+    let realm = params.get("realm").cloned().unwrap_or_default();
+
+    let mut request = client.get(&realm);
+    if let Some(service) = params.get("service") {
+        request = request.query(&[("service", service)]);
+    }
+    if let Some(scope) = params.get("scope") {
+        request = request.query(&[("scope", scope)]);
+    }
+    if let (Some(username), Some(password)) =
+        (&credentials.username, &credentials.password)
+    {
+        request = request.basic_auth(username, Some(password));
+    }
+
+    let token_response: TokenResponse =
+        request.send().await?.error_for_status()?.json().await?;
+
+    token_response
+        .token
+        .or(token_response.access_token)
+        .ok_or(ManifestError::NoToken(realm))
+}
+
+/// Pushes an assembled image index to `registry` so `repository:tag` becomes
+/// a manifest list rather than a single-platform manifest. Authenticates
+/// with whatever the registry's `WWW-Authenticate` challenge demands: plain
+/// Basic for ECR, a bearer-token exchange for Docker Hub/GHCR-style
+/// registries.
+pub async fn push_manifest_list(
+    registry: &str,
+    repository: &str,
+    tag: &str,
+    credentials: &bollard::auth::DockerCredentials,
+    index: &ImageIndex,
+) -> Result<(), ManifestError> {
+    let url = format!("https://{registry}/v2/{repository}/manifests/{tag}");
+    let body = serde_json::to_vec(index).expect("serialize image index");
+
+    let client = reqwest::Client::new();
+    let response = client
+        .put(&url)
+        .header("Content-Type", OCI_IMAGE_INDEX_MEDIA_TYPE)
+        .body(body.clone())
+        .send()
+        .await?;
+
+    if response.status() != reqwest::StatusCode::UNAUTHORIZED {
+        response.error_for_status()?;
+        return Ok(());
+    }
+
+    let challenge = response
+        .headers()
+        .get(reqwest::header::WWW_AUTHENTICATE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default()
+        .to_string();
+
+    let mut retry = client
+        .put(&url)
+        .header("Content-Type", OCI_IMAGE_INDEX_MEDIA_TYPE)
+        .body(body);
+
+    retry = if challenge.starts_with("Bearer") {
+        let token = bearer_token(&client, &challenge, credentials).await?;
+        retry.bearer_auth(token)
+    } else if let (Some(username), Some(password)) =
+        (&credentials.username, &credentials.password)
+    {
+        retry.basic_auth(username, Some(password))
+    } else {
+        retry
+    };
+
+    retry.send().await?.error_for_status()?;
+    Ok(())
+}
@@ -0,0 +1,216 @@
+// Copyright (c) 2023 Murilo Ijanc' <mbsd@m0x.ru>
+//
+// Permission to use, copy, modify, and distribute this software for any
+// purpose with or without fee is hereby granted, provided that the above
+// copyright notice and this permission notice appear in all copies.
+//
+// THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+// WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+// MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+// ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+// WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+// ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+// OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+
+//! Runtime metadata extraction for the final (target) build stage.
+
+use std::collections::HashMap;
+use std::fmt;
+
+use dockerfile_parser::{
+    BreakableStringComponent, Dockerfile, Instruction, MiscInstruction,
+};
+
+/// Everything downstream consumers need to run or generate specs (k8s
+/// manifests, run commands, ...) for the image's final stage.
+#[derive(Debug, Default, Clone)]
+pub struct DockerfileMetadata {
+    pub exposed_ports: Vec<u16>,
+    pub env: HashMap<String, String>,
+    pub labels: HashMap<String, String>,
+    pub entrypoint: Option<Vec<String>>,
+    pub cmd: Option<Vec<String>>,
+    pub base_image: String,
+}
+
+#[derive(Debug)]
+pub enum MetadataError {
+    Parse(dockerfile_parser::Error),
+    NoStages,
+    InvalidPort(String),
+}
+
+impl fmt::Display for MetadataError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MetadataError::Parse(e) => write!(f, "failed to parse Dockerfile: {e}"),
+            MetadataError::NoStages => write!(f, "Dockerfile has no stages"),
+            MetadataError::InvalidPort(p) => {
+                write!(f, "invalid EXPOSE port: {p}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for MetadataError {}
+
+impl From<dockerfile_parser::Error> for MetadataError {
+    fn from(e: dockerfile_parser::Error) -> Self {
+        MetadataError::Parse(e)
+    }
+}
+
+/// Parses `dockerfile` and extracts runtime metadata from its final
+/// (target) stage only — earlier stages are build-time scaffolding and
+/// their `EXPOSE`/`ENV`/`CMD` have no bearing on what actually ships.
+pub fn parse_metadata(
+    dockerfile: &str,
+) -> Result<DockerfileMetadata, MetadataError> {
+    let parsed = Dockerfile::parse(dockerfile)?;
+    let stage = parsed.iter_stages().last().ok_or(MetadataError::NoStages)?;
+
+    let mut metadata = DockerfileMetadata::default();
+
+    for ins in stage.instructions {
+        match ins {
+            Instruction::From(from) => {
+                metadata.base_image = from.image.content.clone();
+            }
+            Instruction::Misc(misc) => apply_misc(&misc, &mut metadata)?,
+            _ => {}
+        }
+    }
+
+    Ok(metadata)
+}
+
+fn apply_misc(
+    misc: &MiscInstruction,
+    metadata: &mut DockerfileMetadata,
+) -> Result<(), MetadataError> {
+    let value = misc_argument_text(misc);
+
+    match misc.instruction.content.as_str() {
+        "EXPOSE" => {
+            for token in value.split_whitespace() {
+                let port_str = token.split('/').next().unwrap_or(token);
+                let port: u16 = port_str
+                    .parse()
+                    .map_err(|_| MetadataError::InvalidPort(token.to_string()))?;
+                metadata.exposed_ports.push(port);
+            }
+        }
+        "ENV" => metadata.env.extend(parse_key_value_pairs(&value)),
+        "LABEL" => metadata.labels.extend(parse_key_value_pairs(&value)),
+        "ENTRYPOINT" => metadata.entrypoint = Some(parse_exec_form(&value)),
+        "CMD" => metadata.cmd = Some(parse_exec_form(&value)),
+        _ => {}
+    }
+
+    Ok(())
+}
+
+fn misc_argument_text(misc: &MiscInstruction) -> String {
+    misc.arguments
+        .components
+        .iter()
+        .filter_map(|c| match c {
+            BreakableStringComponent::String(s) => Some(s.content.as_str()),
+            _ => None,
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Splits on whitespace like `split_whitespace`, except whitespace inside
+/// a `"..."` quoted span doesn't count as a separator. Needed because in
+/// `LABEL description="hello world" version=1.0`, `description="hello world"`
+/// is one token, not two.
+fn tokenize_respecting_quotes(text: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut current = String::new();
+    let mut in_quotes = false;
+
+    for c in text.chars() {
+        match c {
+            '"' => {
+                in_quotes = !in_quotes;
+                current.push(c);
+            }
+            c if c.is_whitespace() && !in_quotes => {
+                if !current.is_empty() {
+                    tokens.push(std::mem::take(&mut current));
+                }
+            }
+            c => current.push(c),
+        }
+    }
+    if !current.is_empty() {
+        tokens.push(current);
+    }
+
+    tokens
+}
+
+/// Parses both the modern `KEY=value KEY2=value2` form and the legacy
+/// single-pair `KEY value` form shared by `ENV` and `LABEL`.
+fn parse_key_value_pairs(text: &str) -> HashMap<String, String> {
+    let tokens = tokenize_respecting_quotes(text);
+    let mut pairs = HashMap::new();
+
+    if tokens.is_empty() {
+        return pairs;
+    }
+
+    if tokens.iter().all(|t| t.contains('=')) {
+        for token in &tokens {
+            if let Some((key, value)) = token.split_once('=') {
+                pairs.insert(key.to_string(), trim_quotes(value));
+            }
+        }
+    } else {
+        let value = tokens[1..].join(" ");
+        pairs.insert(tokens[0].clone(), trim_quotes(&value));
+    }
+
+    pairs
+}
+
+/// Parses the JSON-array exec form (`["a", "b"]`) or falls back to
+/// splitting the shell form on whitespace.
+fn parse_exec_form(text: &str) -> Vec<String> {
+    let trimmed = text.trim();
+    if trimmed.starts_with('[') {
+        trimmed
+            .trim_matches(|c| c == '[' || c == ']')
+            .split(',')
+            .map(|s| trim_quotes(s.trim()))
+            .filter(|s| !s.is_empty())
+            .collect()
+    } else {
+        trimmed.split_whitespace().map(str::to_string).collect()
+    }
+}
+
+fn trim_quotes(s: &str) -> String {
+    s.trim_matches('"').to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn label_with_quoted_spaces_is_one_pair() {
+        let pairs =
+            parse_key_value_pairs(r#"description="hello world" version=1.0"#);
+        assert_eq!(pairs.get("description").map(String::as_str), Some("hello world"));
+        assert_eq!(pairs.get("version").map(String::as_str), Some("1.0"));
+    }
+
+    #[test]
+    fn legacy_single_pair_with_quoted_value() {
+        let pairs = parse_key_value_pairs(r#"maintainer "Jane Doe""#);
+        assert_eq!(pairs.get("maintainer").map(String::as_str), Some("Jane Doe"));
+    }
+}
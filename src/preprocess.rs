@@ -0,0 +1,185 @@
+// Copyright (c) 2023 Murilo Ijanc' <mbsd@m0x.ru>
+//
+// Permission to use, copy, modify, and distribute this software for any
+// purpose with or without fee is hereby granted, provided that the above
+// copyright notice and this permission notice appear in all copies.
+//
+// THE SOFTWARE IS PROVIDED "AS IS" AND THE AUTHOR DISCLAIMS ALL WARRANTIES
+// WITH REGARD TO THIS SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF
+// MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE AUTHOR BE LIABLE FOR
+// ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+// WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN
+// ACTION OF CONTRACT, NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF
+// OR IN CONNECTION WITH THE USE OR PERFORMANCE OF THIS SOFTWARE.
+
+//! `INCLUDE+ <path>` preprocessing, borrowed from the dockerfile-plus
+//! convention, so shared fragments (common `RUN`s, label blocks) can be
+//! factored out of a Dockerfile and spliced back in before parsing.
+
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+const INCLUDE_DIRECTIVE: &str = "INCLUDE+";
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+/// Reads `path` and expands any `INCLUDE+` directives relative to its
+/// directory, returning the fully-spliced Dockerfile.
+pub fn load_dockerfile(path: &Path) -> io::Result<String> {
+    let content = fs::read_to_string(path)?;
+    let base_dir = path.parent().unwrap_or_else(|| Path::new("."));
+    let mut visited = HashSet::new();
+    expand(&content, base_dir, &mut visited, 0)
+}
+
+/// Expands `INCLUDE+ <path>` directives found in `dockerfile`, resolving
+/// relative paths against `base_dir`. Nested includes are expanded
+/// recursively, cycles are rejected, and line order is preserved so stage
+/// ordering in the result stays intact.
+pub fn expand_includes(dockerfile: &str, base_dir: &Path) -> io::Result<String> {
+    let mut visited = HashSet::new();
+    expand(dockerfile, base_dir, &mut visited, 0)
+}
+
+fn expand(
+    content: &str,
+    base_dir: &Path,
+    visited: &mut HashSet<PathBuf>,
+    depth: usize,
+) -> io::Result<String> {
+    if depth > MAX_INCLUDE_DEPTH {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("INCLUDE+ nesting exceeds max depth of {MAX_INCLUDE_DEPTH}"),
+        ));
+    }
+
+    let mut expanded = String::new();
+    for line in content.lines() {
+        match line.trim_start().strip_prefix(INCLUDE_DIRECTIVE) {
+            Some(rest) => {
+                let include_path = base_dir.join(rest.trim());
+                let key = include_path
+                    .canonicalize()
+                    .unwrap_or_else(|_| include_path.clone());
+
+                if !visited.insert(key.clone()) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::Other,
+                        format!(
+                            "INCLUDE+ cycle detected at {}",
+                            include_path.display()
+                        ),
+                    ));
+                }
+
+                let included = fs::read_to_string(&include_path)?;
+                let include_dir =
+                    include_path.parent().unwrap_or(base_dir);
+                let nested =
+                    expand(&included, include_dir, visited, depth + 1)?;
+
+                expanded.push_str(&nested);
+                if !nested.ends_with('\n') {
+                    expanded.push('\n');
+                }
+
+                visited.remove(&key);
+            }
+            None => {
+                expanded.push_str(line);
+                expanded.push('\n');
+            }
+        }
+    }
+    Ok(expanded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        let n = COUNTER.fetch_add(1, Ordering::Relaxed);
+        let dir = std::env::temp_dir()
+            .join(format!("kp-container-preprocess-{name}-{nanos}-{n}"));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn splice_preserves_line_order() {
+        let dir = temp_dir("splice");
+        fs::write(dir.join("base.fragment"), "RUN apt-get update\nRUN apt-get install -y curl\n")
+            .unwrap();
+
+        let dockerfile = "FROM alpine\nINCLUDE+ base.fragment\nENTRYPOINT [\"curl\"]\n";
+        let expanded = expand_includes(dockerfile, &dir).unwrap();
+
+        assert_eq!(
+            expanded,
+            "FROM alpine\nRUN apt-get update\nRUN apt-get install -y curl\nENTRYPOINT [\"curl\"]\n"
+        );
+    }
+
+    #[test]
+    fn nested_includes_are_expanded_recursively() {
+        let dir = temp_dir("nested");
+        fs::write(dir.join("inner.fragment"), "LABEL inner=true\n").unwrap();
+        fs::write(
+            dir.join("outer.fragment"),
+            "RUN echo outer\nINCLUDE+ inner.fragment\n",
+        )
+        .unwrap();
+
+        let dockerfile = "FROM alpine\nINCLUDE+ outer.fragment\n";
+        let expanded = expand_includes(dockerfile, &dir).unwrap();
+
+        assert_eq!(
+            expanded,
+            "FROM alpine\nRUN echo outer\nLABEL inner=true\n"
+        );
+    }
+
+    #[test]
+    fn cycles_are_rejected() {
+        let dir = temp_dir("cycle");
+        fs::write(dir.join("a.fragment"), "INCLUDE+ b.fragment\n").unwrap();
+        fs::write(dir.join("b.fragment"), "INCLUDE+ a.fragment\n").unwrap();
+
+        let dockerfile = "FROM alpine\nINCLUDE+ a.fragment\n";
+        let result = expand_includes(dockerfile, &dir);
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn depth_limit_is_enforced() {
+        let dir = temp_dir("depth");
+        // Each fragment includes the next, one level deeper than
+        // MAX_INCLUDE_DEPTH allows, with no cycle.
+        for i in 0..MAX_INCLUDE_DEPTH {
+            let content = format!("INCLUDE+ frag{}.fragment\n", i + 1);
+            fs::write(dir.join(format!("frag{i}.fragment")), content).unwrap();
+        }
+        fs::write(
+            dir.join(format!("frag{}.fragment", MAX_INCLUDE_DEPTH)),
+            "RUN echo done\n",
+        )
+        .unwrap();
+
+        let dockerfile = "FROM alpine\nINCLUDE+ frag0.fragment\n";
+        let result = expand_includes(dockerfile, &dir);
+
+        assert!(result.is_err());
+    }
+}